@@ -1,9 +1,12 @@
-use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::convert::TryFrom;
-use std::hash::Hash;
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io::Write;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::{Arc, Mutex};
 
@@ -13,6 +16,8 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{http, StatusCode};
 use hyper::{Body, Response, Server};
 
+use lru::LruCache;
+
 use mupdf::pdf::PdfDocument;
 use mupdf::pdf::PdfObject;
 
@@ -80,6 +85,48 @@ fn filter_page_tree<F: FnMut(&PdfObject) -> bool>(
     Ok(())
 }
 
+/// Visits every `Page` node of the tree rooted at `node`, in document
+/// order, without modifying it.
+fn walk_page_tree<F: FnMut(&PdfObject)>(
+    mut node: PdfObject,
+    f: &mut F,
+) -> Result<(), mupdf::Error> {
+    let ty = match node
+        .get_dict("Type")
+        .ok()
+        .flatten()
+        .and_then(|t| t.as_name().ok().map(|s| s.to_owned()))
+    {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+    match &*ty {
+        b"Page" => {
+            f(&node);
+            Ok(())
+        }
+        b"Pages" => {
+            if let Some(mut kids) = node.get_dict("Kids").unwrap() {
+                let kids_len = i32::try_from(kids.len().unwrap()).unwrap();
+                for i in 0..kids_len {
+                    let kid = kids.get_array(i).unwrap().unwrap();
+                    walk_page_tree(kid, f)?;
+                }
+            }
+            Ok(())
+        }
+        _ => panic!("invalid type in page tree"),
+    }
+}
+
+/// A distinct exercise found while walking the page tree, and the
+/// (1-based) pages it spans in document order.
+#[derive(serde::Serialize)]
+pub struct ExerciseInfo {
+    pub exercise: i32,
+    pub pages: Vec<i32>,
+}
+
 pub struct ExerciseExtractor<'a> {
     doc_bytes: &'a [u8],
 }
@@ -168,6 +215,127 @@ impl<'a> ExerciseExtractor<'a> {
         doc.write_to(w).map_err(ExtractError::Mupdf)?;
         Ok(())
     }
+
+    /// Walks the whole page tree in document order, grouping pages by the
+    /// exercise number found on them, so callers can discover what's
+    /// available without guessing and hitting `MissingExercise`.
+    pub fn list_exercises(&self) -> Result<Vec<ExerciseInfo>, ExtractError> {
+        let doc = PdfDocument::from_bytes(&self.doc_bytes).map_err(ExtractError::Mupdf)?;
+        let catalog_id = doc.catalog().map_err(ExtractError::Mupdf)?;
+        let catalog = catalog_id
+            .resolve()
+            .map_err(ExtractError::Mupdf)?
+            .ok_or(ExtractError::InvalidDoc)?;
+        let tree_id = catalog
+            .get_dict("Pages")
+            .map_err(ExtractError::Mupdf)?
+            .ok_or(ExtractError::InvalidDoc)?;
+        let tree = tree_id
+            .resolve()
+            .map_err(ExtractError::Mupdf)?
+            .ok_or(ExtractError::InvalidDoc)?;
+        let mut exercises: Vec<ExerciseInfo> = Vec::new();
+        let mut current_exercise_number: i32 = -1;
+        let mut page_number: i32 = 0;
+        walk_page_tree(tree, &mut |page: &PdfObject| {
+            page_number += 1;
+            if let Some(n) = Self::read_exercise_number(page) {
+                current_exercise_number = n;
+            }
+            if current_exercise_number < 0 {
+                return;
+            }
+            match exercises
+                .iter_mut()
+                .find(|info| info.exercise == current_exercise_number)
+            {
+                Some(info) => info.pages.push(page_number),
+                _ => exercises.push(ExerciseInfo {
+                    exercise: current_exercise_number,
+                    pages: vec![page_number],
+                }),
+            }
+        })
+        .map_err(ExtractError::Mupdf)?;
+        Ok(exercises)
+    }
+}
+
+/// Normalizes a document name and a set of exercise numbers into a
+/// canonical cache key: the exercise numbers are sorted and deduplicated,
+/// so that equivalent requests (e.g. differing only in order) share a
+/// single cache entry.
+fn cache_key(doc_name: &str, exercise_numbers: &[i32]) -> (String, Vec<i32>) {
+    let mut numbers = exercise_numbers.to_vec();
+    numbers.sort_unstable();
+    numbers.dedup();
+    (doc_name.to_owned(), numbers)
+}
+
+/// Computes a strong `ETag` for a cache key. Since each document is
+/// static, the document name plus the normalized exercise-number set
+/// uniquely determines the produced PDF bytes.
+fn etag_for(key: &(String, Vec<i32>)) -> String {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// The name under which the embedded document is registered when
+/// `--documents-dir` is not given.
+const DEFAULT_DOCUMENT_NAME: &str = "exercises";
+
+/// Loads the set of documents to serve, keyed by file stem. When `dir` is
+/// `None`, falls back to a single entry for the embedded document.
+///
+/// Documents are loaded once at startup and live for the lifetime of the
+/// process, so their bytes are leaked to `'static` rather than kept behind
+/// an `Arc`: the `&'static [u8]` is plain `Copy` data, so many blocking
+/// tasks can each build an `ExerciseExtractor` from it with no locking.
+fn load_document_registry(dir: Option<&Path>) -> std::io::Result<HashMap<String, &'static [u8]>> {
+    let mut registry = HashMap::new();
+    match dir {
+        Some(dir) => {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("pdf") {
+                    continue;
+                }
+                let stem = match path.file_stem().and_then(|s| s.to_str()) {
+                    Some(s) => s.to_owned(),
+                    None => continue,
+                };
+                let bytes: &'static [u8] = Box::leak(fs::read(&path)?.into_boxed_slice());
+                registry.insert(stem, bytes);
+            }
+        }
+        None => {
+            registry.insert(DEFAULT_DOCUMENT_NAME.to_owned(), EXERCISES_DOCUMENT.as_slice());
+        }
+    }
+    Ok(registry)
+}
+
+/// The largest span a single `start-end` range token may expand to. Bounds
+/// the work and memory a single request can force onto the server.
+const MAX_RANGE_SPAN: i32 = 1000;
+
+/// Parses one comma-separated token of an exercise query into the
+/// exercise numbers it denotes: either a single integer, or an inclusive
+/// range like `3-7` expanding to `3,4,5,6,7`. Returns `None` on a
+/// malformed token, a reversed range (`start > end`), or a range wider
+/// than `MAX_RANGE_SPAN`.
+fn parse_exercise_token(token: &str) -> Option<Vec<i32>> {
+    if let Ok(n) = token.parse::<i32>() {
+        return Some(vec![n]);
+    }
+    let (start, end) = token.split_once('-')?;
+    let start: i32 = start.parse().ok()?;
+    let end: i32 = end.parse().ok()?;
+    if start > end || i64::from(end) - i64::from(start) > i64::from(MAX_RANGE_SPAN) {
+        return None;
+    }
+    Some((start..=end).collect())
 }
 
 fn has_duplicate_elements<T>(iter: T) -> bool
@@ -179,18 +347,39 @@ where
     iter.into_iter().any(move |x| !uniq.insert(x))
 }
 
-fn not_found() -> http::Result<http::Response<Body>> {
-    Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .header("Content-Type", "text/plain")
-        .body(Body::from("Not found"))
+/// Adds `Access-Control-Allow-Origin`/`Vary` headers when `origin` (the
+/// request's `Origin`, already checked against the configured allow-list)
+/// is present; leaves the builder untouched otherwise.
+fn apply_cors(
+    builder: http::response::Builder,
+    origin: Option<&str>,
+) -> http::response::Builder {
+    match origin {
+        Some(origin) => builder
+            .header("Access-Control-Allow-Origin", origin)
+            .header("Vary", "Origin"),
+        None => builder,
+    }
+}
+
+fn not_found(cors_origin: Option<&str>) -> http::Result<http::Response<Body>> {
+    apply_cors(
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "text/plain"),
+        cors_origin,
+    )
+    .body(Body::from("Not found"))
 }
 
-fn internal_server_error() -> http::Result<http::Response<Body>> {
-    Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .header("Content-Type", "text/plain")
-        .body(Body::from("Internal server error"))
+fn internal_server_error(cors_origin: Option<&str>) -> http::Result<http::Response<Body>> {
+    apply_cors(
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header("Content-Type", "text/plain"),
+        cors_origin,
+    )
+    .body(Body::from("Internal server error"))
 }
 
 const EXERCISES_DOCUMENT: &[u8; 966410] = include_bytes!("exercises.pdf");
@@ -202,56 +391,176 @@ struct Args {
     /// The address to bind to
     #[clap(short, long, default_value = "127.0.0.1:3000")]
     addr: String,
+
+    /// The number of extracted PDFs to keep in the in-memory cache
+    #[clap(long, default_value = "256")]
+    cache_entries: usize,
+
+    /// A directory of `*.pdf` files to serve, keyed by file name (without
+    /// extension); the first path segment of each request selects one of
+    /// them. When omitted, only the embedded document is served, under
+    /// the name `exercises`.
+    #[clap(long)]
+    documents_dir: Option<PathBuf>,
+
+    /// An origin allowed to fetch extracted PDFs cross-origin (repeatable,
+    /// or comma-separated); unset disables CORS entirely
+    #[clap(long, use_delimiter = true)]
+    cors_origin: Vec<String>,
 }
 
-#[tokio::main(flavor = "current_thread")]
+#[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Args::parse();
     let addr: SocketAddr = args.addr.parse()?;
 
-    let exercise_extractor = Arc::new(Mutex::new(ExerciseExtractor::new(EXERCISES_DOCUMENT)));
+    let document_registry = Arc::new(load_document_registry(args.documents_dir.as_deref())?);
+    let cache_entries = match std::num::NonZeroUsize::new(args.cache_entries) {
+        Some(val) => val,
+        None => return Err("cache-entries must be greater than zero".into()),
+    };
+    let extract_cache = Arc::new(Mutex::new(LruCache::<(String, Vec<i32>), Vec<u8>>::new(
+        cache_entries,
+    )));
+
+    let cors_origins = Arc::new(args.cors_origin);
 
     // For every connection, we must make a `Service` to handle all
     // incoming HTTP requests on said connection.
     let make_svc = make_service_fn(|_conn| {
-        let exercise_extractor = exercise_extractor.clone();
+        let document_registry = document_registry.clone();
+        let extract_cache = extract_cache.clone();
+        let cors_origins = cors_origins.clone();
         // This is the `Service` that will handle the connection.
         // `service_fn` is a helper to convert a function that
         // returns a Response into a `Service`.
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                let exercise_extractor = exercise_extractor.clone();
+                let document_registry = document_registry.clone();
+                let extract_cache = extract_cache.clone();
+                let cors_origins = cors_origins.clone();
                 async move {
+                    let allowed_origin = req
+                        .headers()
+                        .get(http::header::ORIGIN)
+                        .and_then(|v| v.to_str().ok())
+                        .filter(|origin| cors_origins.iter().any(|allowed| allowed == origin))
+                        .map(|origin| origin.to_owned());
+                    let cors_origin = allowed_origin.as_deref();
+
+                    // Only treat OPTIONS as a CORS preflight when CORS is
+                    // actually enabled; otherwise leave behavior unchanged.
+                    if !cors_origins.is_empty() && req.method() == hyper::Method::OPTIONS {
+                        return apply_cors(
+                            Response::builder().status(StatusCode::NO_CONTENT),
+                            cors_origin,
+                        )
+                        .header("Access-Control-Allow-Methods", "GET")
+                        .header("Access-Control-Allow-Headers", "If-None-Match")
+                        .body(Body::empty());
+                    }
+
                     let path = req.uri().path();
                     if !path.starts_with('/') {
-                        return not_found();
+                        return not_found(cors_origin);
                     }
-                    let path_without_pdf = path.strip_suffix(".pdf").unwrap_or(path);
-                    let exercise_numbers = match path_without_pdf[1..]
+                    let mut segments = path[1..].splitn(2, '/');
+                    let doc_name = match segments.next() {
+                        Some(s) if !s.is_empty() => s,
+                        _ => return not_found(cors_origin),
+                    };
+                    let exercise_list = match segments.next() {
+                        Some(s) => s,
+                        None => return not_found(cors_origin),
+                    };
+                    let doc_bytes: &'static [u8] = match document_registry.get(doc_name) {
+                        Some(val) => *val,
+                        None => return not_found(cors_origin),
+                    };
+                    if exercise_list == "index.json" {
+                        let exercises = match tokio::task::spawn_blocking(move || {
+                            ExerciseExtractor::new(doc_bytes).list_exercises()
+                        })
+                        .await
+                        {
+                            Ok(Ok(val)) => val,
+                            Ok(Err(_)) | Err(_) => return internal_server_error(cors_origin),
+                        };
+                        let body = match serde_json::to_vec(&exercises) {
+                            Ok(val) => val,
+                            Err(_) => return internal_server_error(cors_origin),
+                        };
+                        return apply_cors(Response::builder(), cors_origin)
+                            .header("Content-Type", "application/json")
+                            .body(Body::from(body));
+                    }
+                    let exercise_list = exercise_list.strip_suffix(".pdf").unwrap_or(exercise_list);
+                    let exercise_numbers = match exercise_list
                         .split(',')
-                        .map(|p| p.parse())
-                        .collect::<Result<Vec<i32>, _>>()
+                        .map(parse_exercise_token)
+                        .collect::<Option<Vec<Vec<i32>>>>()
                     {
-                        Ok(val) => val,
-                        Err(_) => return not_found(),
+                        Some(val) => val.into_iter().flatten().collect::<Vec<i32>>(),
+                        None => return not_found(cors_origin),
                     };
                     if has_duplicate_elements(exercise_numbers.iter()) {
-                        return not_found();
+                        return not_found(cors_origin);
                     }
-                    let res = {
-                        let mut tmp = Vec::new();
-                        let e = match exercise_extractor.lock() {
-                            Ok(val) => val,
-                            Err(_) => return internal_server_error(),
-                        };
-                        match e.extract(&exercise_numbers, &mut tmp) {
-                            Err(ExtractError::MissingExercise) => return not_found(),
-                            Err(_) => return internal_server_error(),
-                            _ => tmp,
+                    let key = cache_key(doc_name, &exercise_numbers);
+                    let etag = etag_for(&key);
+                    let if_none_match = req
+                        .headers()
+                        .get(http::header::IF_NONE_MATCH)
+                        .and_then(|v| v.to_str().ok())
+                        .map(|v| v == etag);
+                    // Only a prior successful extraction (a cache hit, or a
+                    // fresh extraction below) confirms the requested
+                    // exercises exist, so `If-None-Match` can't short-circuit
+                    // to 304 before that's established.
+                    let cached = extract_cache
+                        .lock()
+                        .map_err(|_| ())
+                        .and_then(|mut c| c.get(&key).cloned().ok_or(()))
+                        .ok();
+                    let res = match cached {
+                        Some(cached) => cached,
+                        None => {
+                            let res = match tokio::task::spawn_blocking(move || {
+                                let mut tmp = Vec::new();
+                                ExerciseExtractor::new(doc_bytes)
+                                    .extract(&exercise_numbers, &mut tmp)
+                                    .map(|_| tmp)
+                            })
+                            .await
+                            {
+                                Ok(Ok(tmp)) => tmp,
+                                Ok(Err(ExtractError::MissingExercise)) => {
+                                    return not_found(cors_origin)
+                                }
+                                Ok(Err(_)) | Err(_) => return internal_server_error(cors_origin),
+                            };
+                            match extract_cache.lock() {
+                                Ok(mut c) => {
+                                    c.put(key, res.clone());
+                                }
+                                Err(_) => return internal_server_error(cors_origin),
+                            }
+                            res
                         }
                     };
-                    Response::builder()
+                    if if_none_match == Some(true) {
+                        return apply_cors(
+                            Response::builder().status(StatusCode::NOT_MODIFIED),
+                            cors_origin,
+                        )
+                        .header("ETag", &etag)
+                        .header("Cache-Control", "public, immutable")
+                        .body(Body::empty());
+                    }
+                    apply_cors(Response::builder(), cors_origin)
                         .header("Content-Type", "application/pdf")
+                        .header("ETag", &etag)
+                        .header("Cache-Control", "public, immutable")
                         .body(Body::from(res))
                 }
             }))